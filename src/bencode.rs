@@ -105,6 +105,36 @@ fn parse_dict(input: &[u8]) -> BencodeParserResult {
     Ok((input, BencodeValue::Dict(dict)))
 }
 
+/// A top-level dict parsed with each entry's value annotated with the exact
+/// input subslice it was parsed from, so callers needing the original bytes
+/// of one entry (e.g. to hash it) don't have to re-serialize or re-locate it.
+pub type BencodeDictWithSpans<'a> = IndexMap<&'a [u8], (&'a [u8], BencodeValue<'a>)>;
+
+type BencodeDictWithSpansResult<'a> = IResult<&'a [u8], BencodeDictWithSpans<'a>, BencodeParserError<'a>>;
+
+fn parse_dict_entry_with_span(input: &[u8]) -> IResult<&[u8], (BencodeValue, &[u8], BencodeValue), BencodeParserError> {
+    let (input, key) = parse_byte_string(input)?;
+    let value_start = input;
+    let (input, value) = parse(input)?;
+    let span = &value_start[..value_start.len() - input.len()];
+    Ok((input, (key, span, value)))
+}
+
+pub fn from_bytes_with_spans(input: &[u8]) -> BencodeDictWithSpansResult {
+    let kv_parser = fold_many0(parse_dict_entry_with_span, IndexMap::new(),
+                               |mut acc: BencodeDictWithSpans, (key, span, value)| {
+                                   match key {
+                                       BencodeValue::ByteString(bs) => {
+                                           acc.insert(bs, (span, value));
+                                           acc
+                                       }
+                                       _ => acc
+                                   }
+                               });
+
+    delimited(tag("d"), kv_parser, tag("e"))(input)
+}
+
 pub fn to_bytes(value: &BencodeValue) -> Vec<u8> {
     match value {
         BencodeValue::Integer(num) => {
@@ -145,7 +175,7 @@ pub fn to_bytes(value: &BencodeValue) -> Vec<u8> {
 
 #[cfg(test)]
 mod tests {
-    use crate::bencode::{BencodeValue, BencodeParserError, from_bytes, to_bytes, BencodeParserResult};
+    use crate::bencode::{BencodeValue, BencodeParserError, from_bytes, to_bytes, from_bytes_with_spans, BencodeParserResult};
     use nom::Err::{Failure};
     use nom::sequence::delimited;
     use indexmap::map::IndexMap;
@@ -238,6 +268,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_with_spans_annotates_each_entry_value() {
+        //given
+        let input = "d3:bar4:spam3:fooli1ei2eee".as_bytes();
+
+        //when
+        let result = from_bytes_with_spans(input);
+
+        //then
+        match result {
+            Ok((rest, dict)) => {
+                assert_eq!(dict.get("bar".as_bytes()).map(|(span, _)| *span), Some("4:spam".as_bytes()));
+                assert_eq!(dict.get("foo".as_bytes()).map(|(span, _)| *span), Some("li1ei2ee".as_bytes()));
+                assert_eq!(rest, "".as_bytes());
+            }
+            Err(err) => debug_assert!(false, "error: {:?}", err)
+        }
+    }
+
     #[test]
     fn serialize_integer() {
         //given
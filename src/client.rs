@@ -2,23 +2,34 @@ use crate::torrent::{Torrent, AnnounceUrl};
 use url::{Url, ParseError};
 use std::error::Error;
 use url::form_urlencoded::byte_serialize;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use crate::bencode;
 use crate::bencode::BencodeValue;
 use core::fmt;
 use std::fmt::Formatter;
 use indexmap::map::IndexMap;
 use std::io::Cursor;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+use rand::random;
+
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_ACTION_CONNECT: u32 = 0;
+const UDP_ACTION_ANNOUNCE: u32 = 1;
+const UDP_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+const UDP_MAX_ATTEMPTS: u32 = 4;
 
 #[derive(Debug, PartialEq)]
 pub enum ClientError {
-    TrackerError
+    TrackerError,
+    TrackerFailure(String),
 }
 
 impl fmt::Display for ClientError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            ClientError::TrackerError => write!(f, "{}", "tracker error")
+            ClientError::TrackerError => write!(f, "{}", "tracker error"),
+            ClientError::TrackerFailure(reason) => write!(f, "tracker failure: {}", reason),
         }
     }
 }
@@ -40,11 +51,15 @@ pub struct TrackerUrl(pub String);
 
 impl TrackerUrl {
     pub fn from(torrent: &Torrent, peer_id: &PeerId, port: u32) -> Result<TrackerUrl, Box<dyn Error>> {
-        let mut url = Url::parse(torrent.announce.0.as_str())?;
+        TrackerUrl::for_announce(&torrent.announce, torrent, peer_id, port)
+    }
+
+    pub fn for_announce(announce: &AnnounceUrl, torrent: &Torrent, peer_id: &PeerId, port: u32) -> Result<TrackerUrl, Box<dyn Error>> {
+        let mut url = Url::parse(announce.0.as_str())?;
         let mut query = format!("peer_id={peer_id}&port={port}&uploaded=0&downloaded=0&compact=1&left={left}",
                             peer_id = peer_id.0,
                             port = port,
-                            left = torrent.length);
+                            left = torrent.total_length());
         query.extend("&info_hash=".chars());
         query.extend(byte_serialize(torrent.info_hash.0.as_slice()));
         url.set_query(Some(query.as_str()));
@@ -52,19 +67,43 @@ impl TrackerUrl {
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum PeerAddr {
+    Ip(IpAddr),
+    Host(String),
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Peer {
-    pub ip: Ipv4Addr,
+    pub addr: PeerAddr,
     pub port: u16
 }
 
 impl Peer {
     pub fn from_bytes(bs: &[u8]) -> Peer {
         Peer {
-            ip: Ipv4Addr::new(bs[0],bs[1],bs[2],bs[3]),
+            addr: PeerAddr::Ip(IpAddr::V4(Ipv4Addr::new(bs[0],bs[1],bs[2],bs[3]))),
             port: u16::from_be_bytes([bs[4], bs[5]])
         }
     }
+
+    fn from_dict(dict: &IndexMap<&[u8], BencodeValue>) -> Result<Peer, ClientError> {
+        static IP_KEY: &'static [u8] = "ip".as_bytes();
+        static PORT_KEY: &'static [u8] = "port".as_bytes();
+
+        if_chain! {
+            if let BencodeValue::ByteString(ip) = get_key(dict, IP_KEY)?;
+            if let BencodeValue::Integer(port) = get_key(dict, PORT_KEY)?;
+
+            then {
+                let ip = std::str::from_utf8(ip).map_err(|_| ClientError::TrackerError)?;
+                let addr = ip.parse::<IpAddr>().map(PeerAddr::Ip).unwrap_or_else(|_| PeerAddr::Host(ip.to_string()));
+                Ok(Peer { addr, port: *port as u16 })
+            } else {
+                Err(ClientError::TrackerError)
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -73,35 +112,163 @@ pub struct TrackerResponse {
     pub peers: Vec<Peer>
 }
 
-pub async fn query_tracker(tracker_url: &TrackerUrl) -> Result<TrackerResponse, Box<dyn Error>> {
-    static INTERVAL_KEY: &'static [u8] = "interval".as_bytes();
-    static PEERS_KEY: &'static [u8] = "peers".as_bytes();
+pub async fn query_tracker(torrent: &Torrent, tracker_url: &TrackerUrl, peer_id: &PeerId, port: u32) -> Result<TrackerResponse, Box<dyn Error>> {
+    if Url::parse(tracker_url.0.as_str())?.scheme() == "udp" {
+        return query_udp_tracker(torrent, tracker_url, peer_id, port).await;
+    }
 
     let response = reqwest::get(tracker_url.0.as_str()).await?.bytes().await?;
     let (_, parsed) = bencode::from_bytes(response.as_ref())
         .map_err(|err| {
             ClientError::TrackerError
         })?;
+
+    let dict = match parsed {
+        BencodeValue::Dict(dict) => dict,
+        _ => Err(ClientError::TrackerError)?,
+    };
+
+    Ok(parse_tracker_response(&dict)?)
+}
+
+/// Turns a decoded tracker response dict into a `TrackerResponse`, surfacing
+/// a `failure reason` as `ClientError::TrackerFailure` instead of trying to
+/// read `interval`/`peers` out of it. Split out from `query_tracker` so it
+/// can be exercised without a live tracker.
+fn parse_tracker_response(dict: &IndexMap<&[u8], BencodeValue>) -> Result<TrackerResponse, ClientError> {
+    static INTERVAL_KEY: &'static [u8] = "interval".as_bytes();
+    static PEERS_KEY: &'static [u8] = "peers".as_bytes();
+    static FAILURE_REASON_KEY: &'static [u8] = "failure reason".as_bytes();
+
+    if let Some(BencodeValue::ByteString(reason)) = dict.get(FAILURE_REASON_KEY) {
+        let reason = std::str::from_utf8(reason).unwrap_or("unknown").to_string();
+        return Err(ClientError::TrackerFailure(reason));
+    }
+
     if_chain! {
-        if let BencodeValue::Dict(dict) = parsed;
-        if let BencodeValue::Integer(interval) = get_key(&dict, INTERVAL_KEY)?;
-        if let BencodeValue::ByteString(peers) = get_key(&dict, PEERS_KEY)?;
+        if let BencodeValue::Integer(interval) = get_key(dict, INTERVAL_KEY)?;
+        let peers = get_key(dict, PEERS_KEY)?;
 
         then {
             Ok(TrackerResponse {
                 interval: (*interval) as u64,
-                peers: build_peers(peers)
+                peers: build_peers(peers)?
             })
         } else {
-            Err(ClientError::TrackerError)?
+            Err(ClientError::TrackerError)
         }
     }
 }
 
-fn build_peers(bs: &[u8]) -> Vec<Peer> {
-    bs.chunks(6)
-        .map(Peer::from_bytes)
-        .collect::<Vec<Peer>>()
+/// Tries every tracker across all `announce-list` tiers (falling back to the
+/// single `announce` URL when no tiers are present), in order, returning the
+/// first successful response per BEP-12.
+pub async fn announce(torrent: &Torrent, peer_id: &PeerId, port: u32) -> Result<TrackerResponse, Box<dyn Error>> {
+    let mut last_err: Option<Box<dyn Error>> = None;
+    for tracker in torrent.trackers() {
+        let tracker_url = match TrackerUrl::for_announce(tracker, torrent, peer_id, port) {
+            Ok(tracker_url) => tracker_url,
+            Err(err) => { last_err = Some(err); continue; }
+        };
+        match query_tracker(torrent, &tracker_url, peer_id, port).await {
+            Ok(response) => return Ok(response),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| Box::new(ClientError::TrackerError)))
+}
+
+async fn query_udp_tracker(torrent: &Torrent, tracker_url: &TrackerUrl, peer_id: &PeerId, port: u32) -> Result<TrackerResponse, Box<dyn Error>> {
+    let url = Url::parse(tracker_url.0.as_str())?;
+    let host = url.host_str().ok_or(ClientError::TrackerError)?;
+    let remote_port = url.port().ok_or(ClientError::TrackerError)?;
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((host, remote_port)).await?;
+
+    let connect_transaction_id: u32 = random();
+    let mut connect_request = Vec::with_capacity(16);
+    connect_request.extend_from_slice(&UDP_PROTOCOL_ID.to_be_bytes());
+    connect_request.extend_from_slice(&UDP_ACTION_CONNECT.to_be_bytes());
+    connect_request.extend_from_slice(&connect_transaction_id.to_be_bytes());
+
+    let mut response = [0u8; 16];
+    udp_send_and_receive(&socket, &connect_request, &mut response, UDP_ACTION_CONNECT, connect_transaction_id).await?;
+    let connection_id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+
+    let announce_transaction_id: u32 = random();
+    let mut announce_request = Vec::with_capacity(98);
+    announce_request.extend_from_slice(&connection_id.to_be_bytes());
+    announce_request.extend_from_slice(&UDP_ACTION_ANNOUNCE.to_be_bytes());
+    announce_request.extend_from_slice(&announce_transaction_id.to_be_bytes());
+    announce_request.extend_from_slice(torrent.info_hash.0.as_slice());
+    announce_request.extend_from_slice(peer_id.0.as_bytes());
+    announce_request.extend_from_slice(&0u64.to_be_bytes()); // downloaded
+    announce_request.extend_from_slice(&(torrent.total_length() as u64).to_be_bytes()); // left
+    announce_request.extend_from_slice(&0u64.to_be_bytes()); // uploaded
+    announce_request.extend_from_slice(&0u32.to_be_bytes()); // event: none
+    announce_request.extend_from_slice(&0u32.to_be_bytes()); // ip: default
+    announce_request.extend_from_slice(&random::<u32>().to_be_bytes()); // key
+    announce_request.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: default
+    announce_request.extend_from_slice(&(port as u16).to_be_bytes());
+
+    let mut response = [0u8; 20 + 6 * 64];
+    let n = udp_send_and_receive(&socket, &announce_request, &mut response, UDP_ACTION_ANNOUNCE, announce_transaction_id).await?;
+    if n < 20 {
+        Err(ClientError::TrackerError)?
+    }
+    let interval = u32::from_be_bytes(response[8..12].try_into().unwrap()) as u64;
+
+    Ok(TrackerResponse {
+        interval,
+        peers: build_peers(&BencodeValue::ByteString(&response[20..n]))?,
+    })
+}
+
+/// Sends `request` and waits for a reply matching `expected_action`/
+/// `expected_transaction_id`, retransmitting with exponential backoff on
+/// timeout. Datagrams that don't match (stale responses to an earlier
+/// attempt, or unrelated traffic on the socket) are discarded and waited
+/// past rather than treated as a protocol error, since UDP may reorder or
+/// duplicate packets.
+async fn udp_send_and_receive(socket: &UdpSocket, request: &[u8], response: &mut [u8], expected_action: u32, expected_transaction_id: u32) -> Result<usize, Box<dyn Error>> {
+    for attempt in 0..UDP_MAX_ATTEMPTS {
+        socket.send(request).await?;
+        let wait = udp_recv_matching(socket, response, expected_action, expected_transaction_id);
+        match timeout(UDP_RETRANSMIT_TIMEOUT * 2u32.pow(attempt), wait).await {
+            Ok(result) => return Ok(result?),
+            Err(_) => continue,
+        }
+    }
+    Err(ClientError::TrackerError)?
+}
+
+/// Keeps receiving on `socket` until a datagram whose action/transaction id
+/// match what was just sent shows up, silently dropping anything else (a
+/// stale reply to an earlier attempt, or unrelated traffic).
+async fn udp_recv_matching(socket: &UdpSocket, response: &mut [u8], expected_action: u32, expected_transaction_id: u32) -> std::io::Result<usize> {
+    loop {
+        let n = socket.recv(response).await?;
+        if n >= 8
+            && u32::from_be_bytes(response[0..4].try_into().unwrap()) == expected_action
+            && u32::from_be_bytes(response[4..8].try_into().unwrap()) == expected_transaction_id {
+            return Ok(n);
+        }
+    }
+}
+
+fn build_peers(value: &BencodeValue) -> Result<Vec<Peer>, ClientError> {
+    match value {
+        BencodeValue::ByteString(bs) => Ok(bs.chunks(6).map(Peer::from_bytes).collect()),
+        BencodeValue::List(entries) => entries.iter().map(|entry| {
+            if let BencodeValue::Dict(dict) = entry {
+                Peer::from_dict(dict)
+            } else {
+                Err(ClientError::TrackerError)
+            }
+        }).collect(),
+        _ => Err(ClientError::TrackerError),
+    }
 }
 
 fn get_key<'a>(dict: &'a IndexMap<&[u8], BencodeValue<'a>>, key: &'static [u8]) -> Result<&'a BencodeValue<'a>, ClientError> {
@@ -112,7 +279,10 @@ fn get_key<'a>(dict: &'a IndexMap<&[u8], BencodeValue<'a>>, key: &'static [u8])
 mod test {
     use crate::torrent::Torrent;
     use std::error::Error;
-    use crate::client::{TrackerUrl, PeerId, query_tracker};
+    use std::net::{IpAddr, Ipv4Addr};
+    use indexmap::map::IndexMap;
+    use crate::bencode::BencodeValue;
+    use crate::client::{TrackerUrl, PeerId, ClientError, Peer, PeerAddr, query_tracker, build_peers, parse_tracker_response};
 
     #[test]
     fn create_tracker_url() -> Result<(), Box<dyn Error>> {
@@ -145,10 +315,87 @@ mod test {
 
         //when
         let url = TrackerUrl::from(&torrent, &peer_id, port)?;
-        let response = query_tracker(&url).await?;
+        let response = query_tracker(&torrent, &url, &peer_id, port).await?;
 
         //then
         println!("{:?}", response);
         Ok(())
     }
+
+    #[test]
+    fn build_peers_from_compact_bytestring() -> Result<(), ClientError> {
+        //given
+        let bytes = [127, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 1, 0x1A, 0xE2];
+
+        //when
+        let peers = build_peers(&BencodeValue::ByteString(&bytes))?;
+
+        //then
+        assert_eq!(peers, vec![
+            Peer { addr: PeerAddr::Ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))), port: 6881 },
+            Peer { addr: PeerAddr::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))), port: 6882 },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn build_peers_from_dict_list_with_ip_literal() -> Result<(), ClientError> {
+        //given
+        let mut peer = IndexMap::new();
+        peer.insert("ip".as_bytes(), BencodeValue::ByteString("127.0.0.1".as_bytes()));
+        peer.insert("port".as_bytes(), BencodeValue::Integer(6881));
+        let entries = BencodeValue::List(vec![BencodeValue::Dict(peer)]);
+
+        //when
+        let peers = build_peers(&entries)?;
+
+        //then
+        assert_eq!(peers, vec![Peer { addr: PeerAddr::Ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))), port: 6881 }]);
+        Ok(())
+    }
+
+    #[test]
+    fn build_peers_from_dict_list_with_hostname() -> Result<(), ClientError> {
+        //given
+        let mut peer = IndexMap::new();
+        peer.insert("ip".as_bytes(), BencodeValue::ByteString("peer.example.com".as_bytes()));
+        peer.insert("port".as_bytes(), BencodeValue::Integer(6881));
+        let entries = BencodeValue::List(vec![BencodeValue::Dict(peer)]);
+
+        //when
+        let peers = build_peers(&entries)?;
+
+        //then
+        assert_eq!(peers, vec![Peer { addr: PeerAddr::Host("peer.example.com".to_string()), port: 6881 }]);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_tracker_response_surfaces_failure_reason() {
+        //given
+        let mut dict = IndexMap::new();
+        dict.insert("failure reason".as_bytes(), BencodeValue::ByteString("torrent not found".as_bytes()));
+
+        //when
+        let result = parse_tracker_response(&dict);
+
+        //then
+        assert_eq!(result, Err(ClientError::TrackerFailure("torrent not found".to_string())));
+    }
+
+    #[test]
+    fn parse_tracker_response_reads_interval_and_peers() -> Result<(), ClientError> {
+        //given
+        let mut dict = IndexMap::new();
+        dict.insert("interval".as_bytes(), BencodeValue::Integer(1800));
+        dict.insert("peers".as_bytes(), BencodeValue::ByteString(&[127, 0, 0, 1, 0x1A, 0xE1]));
+
+        //when
+        let response = parse_tracker_response(&dict)?;
+
+        //then
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.peers, vec![Peer { addr: PeerAddr::Ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))), port: 6881 }]);
+        Ok(())
+    }
 }
\ No newline at end of file
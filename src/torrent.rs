@@ -1,16 +1,16 @@
 use crate::bencode;
-use crate::bencode::BencodeValue;
+use crate::bencode::{BencodeValue, BencodeDictWithSpans};
 use serde::private::ser::constrain;
 use nom::lib::std::collections::HashMap;
 use nom::lib::std::collections::hash_map::RandomState;
 use nom::lib::std::slice::Chunks;
-use nom::{FindSubstring, InputTake};
 use sha1::{Sha1, Digest};
 use hex_literal::hex;
 use indexmap::map::IndexMap;
 use core::fmt;
 use nom::lib::std::fmt::Formatter;
 use std::error::Error;
+use url::form_urlencoded::byte_serialize;
 
 #[derive(Debug, PartialEq)]
 pub enum TorrentError {
@@ -39,11 +39,27 @@ impl fmt::Display for InfoHash {
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub struct FileEntry {
+    pub length: i64,
+    pub path: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TorrentFiles {
+    Single { length: i64 },
+    Multi { files: Vec<FileEntry> },
+}
+
+const BLOCK_LEN: u32 = 16384;
+
 #[derive(Debug, PartialEq)]
 pub struct Torrent {
     pub announce: AnnounceUrl,
+    pub announce_list: Vec<Vec<AnnounceUrl>>,
+    pub nodes: Vec<(String, i64)>,
     pub name: String,
-    pub length: i64,
+    pub files: TorrentFiles,
     pub piece_length: i64,
     pub info_hash: InfoHash,
     pieces: Vec<u8>,
@@ -54,33 +70,31 @@ impl Torrent {
         static ANNOUNCE_KEY: &'static [u8] = "announce".as_bytes();
         static INFO_KEY: &'static [u8] = "info".as_bytes();
         static NAME_KEY: &'static [u8] = "name".as_bytes();
-        static LENGTH_KEY: &'static [u8] = "length".as_bytes();
         static PIECE_LENGTH_KEY: &'static [u8] = "piece length".as_bytes();
         static PIECES_KEY: &'static [u8] = "pieces".as_bytes();
 
-        let parsed = bencode::from_bytes(bytes)
-            .map(|parsed| parsed.1)
+        let (_, dict) = bencode::from_bytes_with_spans(bytes)
             .map_err(|err| TorrentError::InvalidInput)?;
 
-        let sub = bytes.find_substring("4:info").ok_or(TorrentError::InvalidInput)?;
-
         if_chain! {
-            if let BencodeValue::Dict(dict) = parsed;
-            if let BencodeValue::ByteString(announce) = get_key(&dict, ANNOUNCE_KEY)?;
-            let wrapped_info_dict = get_key(&dict, INFO_KEY)?;
+            if let BencodeValue::ByteString(announce) = get_key_spanned(&dict, ANNOUNCE_KEY)?;
+            let info_raw = get_span(&dict, INFO_KEY)?;
+            let wrapped_info_dict = get_key_spanned(&dict, INFO_KEY)?;
             if let BencodeValue::Dict(info_dict) = wrapped_info_dict;
             if let BencodeValue::ByteString(name) = get_key(&info_dict, NAME_KEY)?;
-            if let BencodeValue::Integer(length) = get_key(&info_dict, LENGTH_KEY)?;
             if let BencodeValue::Integer(piece_length) = get_key(&info_dict, PIECE_LENGTH_KEY)?;
             if let BencodeValue::ByteString(pieces) = get_key(&info_dict, PIECES_KEY)?;
+            let files = parse_files(&info_dict)?;
 
             then {
                 Ok(Torrent {
                     announce: AnnounceUrl(std::str::from_utf8(announce).unwrap().to_string()),
+                    announce_list: parse_announce_list(&dict),
+                    nodes: parse_nodes(&dict),
                     name: std::str::from_utf8(name).unwrap().to_string(),
-                    length: *length,
+                    files,
                     piece_length: *piece_length,
-                    info_hash: InfoHash(build_info_hash(bencode::to_bytes(&wrapped_info_dict).as_slice())),
+                    info_hash: InfoHash(build_info_hash(info_raw)),
                     pieces: pieces.to_vec() })
 
             } else {
@@ -92,12 +106,146 @@ impl Torrent {
     pub fn pieces(&self) -> Chunks<'_, u8> {
         self.pieces.chunks(20)
     }
+
+    /// All known announce URLs, tier by tier: the `announce-list` tiers if
+    /// present, falling back to the single `announce` URL as the only tier.
+    pub fn trackers(&self) -> Vec<&AnnounceUrl> {
+        if self.announce_list.is_empty() {
+            vec![&self.announce]
+        } else {
+            self.announce_list.iter().flatten().collect()
+        }
+    }
+
+    pub fn total_length(&self) -> i64 {
+        match &self.files {
+            TorrentFiles::Single { length } => *length,
+            TorrentFiles::Multi { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    pub fn piece_len(&self, piece_index: u32) -> u32 {
+        let piece_length = self.piece_length as u32;
+        if piece_index as usize == self.pieces().count() - 1 {
+            let remainder = self.total_length() as u32 % piece_length;
+            if remainder == 0 { piece_length } else { remainder }
+        } else {
+            piece_length
+        }
+    }
+
+    pub fn blocks_per_piece(&self, piece_index: u32) -> u32 {
+        let piece_len = self.piece_len(piece_index);
+        (piece_len + BLOCK_LEN - 1) / BLOCK_LEN
+    }
+
+    pub fn block_len(&self, piece_index: u32, block_index: u32) -> u32 {
+        let piece_len = self.piece_len(piece_index);
+        let remainder = piece_len % BLOCK_LEN;
+        if block_index == self.blocks_per_piece(piece_index) - 1 && remainder != 0 {
+            remainder
+        } else {
+            BLOCK_LEN
+        }
+    }
+
+    pub fn magnet(&self) -> String {
+        let dn: String = byte_serialize(self.name.as_bytes()).collect();
+        let trackers: String = self.trackers().iter()
+            .map(|tracker| format!("&tr={}", byte_serialize(tracker.0.as_bytes()).collect::<String>()))
+            .collect();
+        format!("magnet:?xt=urn:btih:{info_hash}&dn={dn}{trackers}",
+                info_hash = self.info_hash,
+                dn = dn,
+                trackers = trackers)
+    }
+}
+
+fn parse_announce_list(dict: &BencodeDictWithSpans) -> Vec<Vec<AnnounceUrl>> {
+    static ANNOUNCE_LIST_KEY: &'static [u8] = "announce-list".as_bytes();
+
+    match dict.get(ANNOUNCE_LIST_KEY).map(|(_, value)| value) {
+        Some(BencodeValue::List(tiers)) => tiers.iter().map(|tier| {
+            if let BencodeValue::List(urls) = tier {
+                urls.iter().filter_map(|url| {
+                    if let BencodeValue::ByteString(bs) = url {
+                        std::str::from_utf8(bs).ok().map(|s| AnnounceUrl(s.to_string()))
+                    } else {
+                        None
+                    }
+                }).collect()
+            } else {
+                Vec::new()
+            }
+        }).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_nodes(dict: &BencodeDictWithSpans) -> Vec<(String, i64)> {
+    static NODES_KEY: &'static [u8] = "nodes".as_bytes();
+
+    match dict.get(NODES_KEY).map(|(_, value)| value) {
+        Some(BencodeValue::List(nodes)) => nodes.iter().filter_map(|node| {
+            if let BencodeValue::List(pair) = node {
+                if let [BencodeValue::ByteString(host), BencodeValue::Integer(port)] = pair.as_slice() {
+                    return std::str::from_utf8(host).ok().map(|s| (s.to_string(), *port));
+                }
+            }
+            None
+        }).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_files(info_dict: &IndexMap<&[u8], BencodeValue>) -> Result<TorrentFiles, TorrentError> {
+    static LENGTH_KEY: &'static [u8] = "length".as_bytes();
+    static FILES_KEY: &'static [u8] = "files".as_bytes();
+    static PATH_KEY: &'static [u8] = "path".as_bytes();
+
+    if let Some(BencodeValue::List(files)) = info_dict.get(FILES_KEY) {
+        let files = files.iter().map(|file| {
+            if_chain! {
+                if let BencodeValue::Dict(file_dict) = file;
+                if let BencodeValue::Integer(length) = get_key(file_dict, LENGTH_KEY)?;
+                if let BencodeValue::List(path) = get_key(file_dict, PATH_KEY)?;
+
+                then {
+                    let path = path.iter().map(|component| {
+                        if let BencodeValue::ByteString(bs) = component {
+                            std::str::from_utf8(bs).map(str::to_string).map_err(|_| TorrentError::InvalidInput)
+                        } else {
+                            Err(TorrentError::InvalidInput)
+                        }
+                    }).collect::<Result<Vec<String>, TorrentError>>()?;
+
+                    Ok(FileEntry { length: *length, path })
+                } else {
+                    Err(TorrentError::InvalidInput)?
+                }
+            }
+        }).collect::<Result<Vec<FileEntry>, TorrentError>>()?;
+
+        Ok(TorrentFiles::Multi { files })
+    } else if let BencodeValue::Integer(length) = get_key(info_dict, LENGTH_KEY)? {
+        Ok(TorrentFiles::Single { length: *length })
+    } else {
+        Err(TorrentError::InvalidInput)
+    }
 }
 
 fn get_key<'a>(dict: &'a IndexMap<&[u8], BencodeValue<'a>>, key: &'static [u8]) -> Result<&'a BencodeValue<'a>, TorrentError> {
     dict.get(key).ok_or(TorrentError::InvalidInput)
 }
 
+fn get_key_spanned<'a>(dict: &'a BencodeDictWithSpans<'a>, key: &'static [u8]) -> Result<&'a BencodeValue<'a>, TorrentError> {
+    dict.get(key).map(|(_, value)| value).ok_or(TorrentError::InvalidInput)
+}
+
+fn get_span<'a>(dict: &'a BencodeDictWithSpans<'a>, key: &'static [u8]) -> Result<&'a [u8], TorrentError> {
+    dict.get(key).map(|(span, _)| *span).ok_or(TorrentError::InvalidInput)
+}
+
 fn build_info_hash(info_dict: &[u8]) -> Vec<u8> {
     let mut hasher = Sha1::new();
     hasher.update(info_dict);
@@ -106,7 +254,27 @@ fn build_info_hash(info_dict: &[u8]) -> Vec<u8> {
 
 #[cfg(test)]
 mod test {
-    use crate::torrent::{Torrent, TorrentError, AnnounceUrl, InfoHash};
+    use crate::torrent::{Torrent, TorrentError, AnnounceUrl, InfoHash, TorrentFiles, FileEntry};
+    use crate::bencode;
+    use crate::bencode::BencodeValue;
+    use indexmap::map::IndexMap;
+    use sha1::{Sha1, Digest};
+
+    fn multi_file_info(files: BencodeValue) -> IndexMap<&'static [u8], BencodeValue<'static>> {
+        let mut info = IndexMap::new();
+        info.insert("name".as_bytes(), BencodeValue::ByteString("multi".as_bytes()));
+        info.insert("piece length".as_bytes(), BencodeValue::Integer(16384));
+        info.insert("pieces".as_bytes(), BencodeValue::ByteString(&[0u8; 20]));
+        info.insert("files".as_bytes(), files);
+        info
+    }
+
+    fn torrent_bytes_with_info(info: IndexMap<&'static [u8], BencodeValue<'static>>) -> Vec<u8> {
+        let mut dict = IndexMap::new();
+        dict.insert("announce".as_bytes(), BencodeValue::ByteString("http://tracker.example/announce".as_bytes()));
+        dict.insert("info".as_bytes(), BencodeValue::Dict(info));
+        bencode::to_bytes(&BencodeValue::Dict(dict))
+    }
 
     #[test]
     fn parse_torrent() -> Result<(), TorrentError> {
@@ -119,13 +287,239 @@ mod test {
         //then
         assert_eq!(torrent.announce, AnnounceUrl("http://tracker.archlinux.org:6969/announce".to_string()));
         assert_eq!(torrent.name, "archlinux-2020.06.01-x86_64.iso".to_string());
-        assert_eq!(torrent.length, 694157312);
+        assert_eq!(torrent.files, TorrentFiles::Single { length: 694157312 });
+        assert_eq!(torrent.announce_list, Vec::<Vec<AnnounceUrl>>::new());
+        assert_eq!(torrent.nodes, Vec::<(String, i64)>::new());
         assert_eq!(torrent.piece_length, 524288);
-        assert_eq!(torrent.pieces().count(), (torrent.length / torrent.piece_length) as usize);
+        assert_eq!(torrent.total_length(), 694157312);
+        assert_eq!(torrent.pieces().count(), (torrent.total_length() / torrent.piece_length) as usize);
         assert_eq!(torrent.info_hash, InfoHash(hex!("e79d1fac0e60598bf0f1133487852d81cf716ced").to_vec()));
         Ok(())
     }
 
+    #[test]
+    fn parse_multi_file_torrent() -> Result<(), TorrentError> {
+        //given
+        let files = BencodeValue::List(vec![
+            BencodeValue::Dict({
+                let mut file = IndexMap::new();
+                file.insert("length".as_bytes(), BencodeValue::Integer(10));
+                file.insert("path".as_bytes(), BencodeValue::List(vec![
+                    BencodeValue::ByteString("a".as_bytes()),
+                    BencodeValue::ByteString("b.txt".as_bytes())]));
+                file
+            }),
+            BencodeValue::Dict({
+                let mut file = IndexMap::new();
+                file.insert("length".as_bytes(), BencodeValue::Integer(20));
+                file.insert("path".as_bytes(), BencodeValue::List(vec![
+                    BencodeValue::ByteString("c.txt".as_bytes())]));
+                file
+            }),
+        ]);
+        let bytes = torrent_bytes_with_info(multi_file_info(files));
+
+        //when
+        let torrent = Torrent::from_bytes(&bytes)?;
+
+        //then
+        assert_eq!(torrent.files, TorrentFiles::Multi { files: vec![
+            FileEntry { length: 10, path: vec!["a".to_string(), "b.txt".to_string()] },
+            FileEntry { length: 20, path: vec!["c.txt".to_string()] },
+        ]});
+        assert_eq!(torrent.total_length(), 30);
+        Ok(())
+    }
+
+    #[test]
+    fn multi_file_path_with_invalid_utf8_is_rejected() {
+        //given
+        let files = BencodeValue::List(vec![BencodeValue::Dict({
+            let mut file = IndexMap::new();
+            file.insert("length".as_bytes(), BencodeValue::Integer(10));
+            file.insert("path".as_bytes(), BencodeValue::List(vec![
+                BencodeValue::ByteString(&[0xff, 0xfe])]));
+            file
+        })]);
+        let bytes = torrent_bytes_with_info(multi_file_info(files));
+
+        //when
+        let result = Torrent::from_bytes(&bytes);
+
+        //then
+        assert_eq!(result, Err(TorrentError::InvalidInput));
+    }
+
+    #[test]
+    fn parse_torrent_with_announce_list_and_nodes() -> Result<(), TorrentError> {
+        //given
+        let announce_list = BencodeValue::List(vec![
+            BencodeValue::List(vec![
+                BencodeValue::ByteString("http://tracker1.example/announce".as_bytes()),
+                BencodeValue::ByteString("http://tracker2.example/announce".as_bytes())]),
+            BencodeValue::List(vec![
+                BencodeValue::ByteString("udp://tracker3.example:80".as_bytes())]),
+            BencodeValue::List(vec![BencodeValue::Integer(1)]), // malformed tier, dropped entry
+        ]);
+        let nodes = BencodeValue::List(vec![
+            BencodeValue::List(vec![
+                BencodeValue::ByteString("router.example.com".as_bytes()),
+                BencodeValue::Integer(6881)]),
+            BencodeValue::List(vec![BencodeValue::ByteString("bad.example.com".as_bytes())]), // malformed pair, dropped
+        ]);
+
+        let mut info = IndexMap::new();
+        info.insert("length".as_bytes(), BencodeValue::Integer(5));
+        info.insert("name".as_bytes(), BencodeValue::ByteString("t".as_bytes()));
+        info.insert("piece length".as_bytes(), BencodeValue::Integer(16384));
+        info.insert("pieces".as_bytes(), BencodeValue::ByteString(&[0u8; 20]));
+
+        let mut dict = IndexMap::new();
+        dict.insert("announce".as_bytes(), BencodeValue::ByteString("http://tracker.example/announce".as_bytes()));
+        dict.insert("announce-list".as_bytes(), announce_list);
+        dict.insert("nodes".as_bytes(), nodes);
+        dict.insert("info".as_bytes(), BencodeValue::Dict(info));
+        let bytes = bencode::to_bytes(&BencodeValue::Dict(dict));
+
+        //when
+        let torrent = Torrent::from_bytes(&bytes)?;
+
+        //then
+        assert_eq!(torrent.announce_list, vec![
+            vec![AnnounceUrl("http://tracker1.example/announce".to_string()),
+                 AnnounceUrl("http://tracker2.example/announce".to_string())],
+            vec![AnnounceUrl("udp://tracker3.example:80".to_string())],
+            vec![],
+        ]);
+        assert_eq!(torrent.nodes, vec![("router.example.com".to_string(), 6881)]);
+        Ok(())
+    }
+
+    #[test]
+    fn non_utf8_announce_list_and_nodes_entries_are_dropped_not_panicked() -> Result<(), TorrentError> {
+        //given
+        let announce_list = BencodeValue::List(vec![
+            BencodeValue::List(vec![
+                BencodeValue::ByteString(&[0xff, 0xfe]),
+                BencodeValue::ByteString("http://tracker2.example/announce".as_bytes())]),
+        ]);
+        let nodes = BencodeValue::List(vec![
+            BencodeValue::List(vec![
+                BencodeValue::ByteString(&[0xff, 0xfe]),
+                BencodeValue::Integer(6881)]),
+            BencodeValue::List(vec![
+                BencodeValue::ByteString("router.example.com".as_bytes()),
+                BencodeValue::Integer(6882)]),
+        ]);
+
+        let mut info = IndexMap::new();
+        info.insert("length".as_bytes(), BencodeValue::Integer(5));
+        info.insert("name".as_bytes(), BencodeValue::ByteString("t".as_bytes()));
+        info.insert("piece length".as_bytes(), BencodeValue::Integer(16384));
+        info.insert("pieces".as_bytes(), BencodeValue::ByteString(&[0u8; 20]));
+
+        let mut dict = IndexMap::new();
+        dict.insert("announce".as_bytes(), BencodeValue::ByteString("http://tracker.example/announce".as_bytes()));
+        dict.insert("announce-list".as_bytes(), announce_list);
+        dict.insert("nodes".as_bytes(), nodes);
+        dict.insert("info".as_bytes(), BencodeValue::Dict(info));
+        let bytes = bencode::to_bytes(&BencodeValue::Dict(dict));
+
+        //when
+        let torrent = Torrent::from_bytes(&bytes)?;
+
+        //then
+        assert_eq!(torrent.announce_list, vec![
+            vec![AnnounceUrl("http://tracker2.example/announce".to_string())],
+        ]);
+        assert_eq!(torrent.nodes, vec![("router.example.com".to_string(), 6882)]);
+        Ok(())
+    }
+
+    #[test]
+    fn torrent_to_magnet() -> Result<(), TorrentError> {
+        //given
+        let input = include_bytes!("../resources/archlinux-2020.06.01-x86_64.iso.torrent");
+        let torrent = Torrent::from_bytes(input)?;
+
+        //when
+        let result = torrent.magnet();
+
+        //then
+        assert_eq!(result, "magnet:?xt=urn:btih:e79d1fac0e60598bf0f1133487852d81cf716ced\
+        &dn=archlinux-2020.06.01-x86_64.iso\
+        &tr=http%3A%2F%2Ftracker.archlinux.org%3A6969%2Fannounce");
+        Ok(())
+    }
+
+    #[test]
+    fn piece_and_block_geometry() -> Result<(), TorrentError> {
+        //given
+        let input = include_bytes!("../resources/archlinux-2020.06.01-x86_64.iso.torrent");
+        let torrent = Torrent::from_bytes(input)?;
+        let last_piece = (torrent.pieces().count() - 1) as u32;
+
+        //then
+        assert_eq!(torrent.piece_len(0), 524288);
+        assert_eq!(torrent.piece_len(last_piece), 524288);
+        assert_eq!(torrent.blocks_per_piece(0), 524288 / 16384);
+        assert_eq!(torrent.block_len(0, 0), 16384);
+        Ok(())
+    }
+
+    #[test]
+    fn piece_and_block_geometry_with_remainder() -> Result<(), TorrentError> {
+        //given - total length of 52768 over a 32768-byte piece length leaves
+        //a short last piece (20000 bytes), itself spanning a short last block
+        let mut info = IndexMap::new();
+        info.insert("length".as_bytes(), BencodeValue::Integer(52768));
+        info.insert("name".as_bytes(), BencodeValue::ByteString("t".as_bytes()));
+        info.insert("piece length".as_bytes(), BencodeValue::Integer(32768));
+        info.insert("pieces".as_bytes(), BencodeValue::ByteString(&[0u8; 40])); // 2 pieces
+        let bytes = torrent_bytes_with_info(info);
+
+        //when
+        let torrent = Torrent::from_bytes(&bytes)?;
+
+        //then
+        assert_eq!(torrent.piece_len(0), 32768);
+        assert_eq!(torrent.piece_len(1), 20000);
+        assert_eq!(torrent.blocks_per_piece(1), 2);
+        assert_eq!(torrent.block_len(1, 0), 16384);
+        assert_eq!(torrent.block_len(1, 1), 3616);
+        Ok(())
+    }
+
+    #[test]
+    fn info_hash_unaffected_by_decoy_bytes_in_earlier_fields() -> Result<(), TorrentError> {
+        //given - a byte string field sorted before "info" whose bencoded
+        //content happens to contain the literal bytes "4:info"
+        let info = || {
+            let mut info = IndexMap::new();
+            info.insert("length".as_bytes(), BencodeValue::Integer(5));
+            info.insert("name".as_bytes(), BencodeValue::ByteString("t".as_bytes()));
+            info.insert("piece length".as_bytes(), BencodeValue::Integer(16384));
+            info.insert("pieces".as_bytes(), BencodeValue::ByteString(&[0u8; 20]));
+            info
+        };
+
+        let mut dict = IndexMap::new();
+        dict.insert("announce".as_bytes(), BencodeValue::ByteString("http://tracker.example/announce".as_bytes()));
+        dict.insert("comment".as_bytes(), BencodeValue::ByteString("xxx4:infoyyy".as_bytes()));
+        dict.insert("info".as_bytes(), BencodeValue::Dict(info()));
+        let bytes = bencode::to_bytes(&BencodeValue::Dict(dict));
+
+        //when
+        let torrent = Torrent::from_bytes(&bytes)?;
+
+        //then
+        let info_bytes = bencode::to_bytes(&BencodeValue::Dict(info()));
+        let mut hasher = Sha1::new();
+        hasher.update(&info_bytes);
+        assert_eq!(torrent.info_hash, InfoHash(hasher.finalize().to_vec()));
+        Ok(())
+    }
+
     #[test]
     fn info_hash_to_string() {
         //given+